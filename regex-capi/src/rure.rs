@@ -5,7 +5,10 @@ use ::regex::internal::{Exec, ExecBuilder, RegexOptions};
 use ::regex::internal::RegularExpression;
 use ::libc::{c_char, size_t};
 
+use ::std::borrow::Cow;
+use ::std::cmp;
 use ::std::collections::HashMap;
+use ::std::collections::VecDeque;
 use ::std::ops::Deref;
 use ::std::ffi::{CStr, CString};
 use ::std::ptr;
@@ -39,6 +42,10 @@ const RURE_FLAG_SPACE: u32 = 1 << 4;
 const RURE_FLAG_UNICODE: u32 = 1 << 5;
 const RURE_DEFAULT_FLAGS: u32 = RURE_FLAG_UNICODE;
 
+// Flags accepted by `rure_replace`/`rure_replace_all`/`rure_captures_expand`.
+// This is a separate namespace from the `RURE_FLAG_*` compile flags above.
+const RURE_REPLACE_LITERAL: u32 = 1 << 0;
+
 
 #[repr(C)]
 pub struct rure_match {
@@ -54,6 +61,64 @@ pub struct Iter {
     last_match: Option<usize>,
 }
 
+pub struct SetIter {
+    re: *const RegexSet,
+    last_end: usize,
+    last_match: Option<usize>,
+}
+
+// Iterates the substrings between matches of `re`, the way `Regex::split`/
+// `splitn` does. `limit` mirrors `splitn`'s cap on the number of pieces
+// yielded (`None` for the unbounded `split`); the final piece, whenever
+// it's reached, is the rest of the haystack rather than the result of a
+// match.
+//
+// `piece_start` and `search_pos` are deliberately separate: `piece_start`
+// is the true end of the last accepted match, i.e. the boundary used to
+// slice out the next piece, while `search_pos` is where the next
+// `find_at` resumes, which gets nudged an extra byte past an empty match
+// purely to guarantee search progress. Collapsing them into one field
+// makes the piece after an empty match start one byte too late.
+pub struct Split {
+    re: *const Regex,
+    piece_start: usize,
+    search_pos: usize,
+    last_match: Option<usize>,
+    limit: Option<usize>,
+    done: bool,
+}
+
+// Searches a haystack that arrives in chunks over time, the way a
+// streaming automaton searches over a sliding buffer. `buf` is a rolling
+// window of not-yet-fully-searched bytes and `offset` is the absolute
+// position of `buf[0]` in the overall (unbounded) stream; every match
+// found in `buf` is translated to absolute coordinates before being
+// queued in `pending`.
+//
+// `window_len` is the caller-declared maximum match length: a match
+// isn't reported until its end is at least `window_len` bytes before the
+// buffer's tail, so that a match which might still be extended (or
+// invalidated) by not-yet-arrived bytes is never reported early. Note
+// that this means `^`/`$` (in multi-line mode) are always evaluated
+// against `buf`'s current boundaries, not the overall stream's line
+// boundaries. `\A`/`\z` are anchored to index 0/the end of `buf` as it
+// stands. Index 0 is the true stream start only while `offset` is 0, and
+// a feed can drain a prefix of `buf` whether or not it confirmed a match.
+// The end of `buf` is the true stream end only in `rure_stream_end`'s
+// final flush; before that, a match ending at the tail is held back by
+// the margin above unless `window_len` is 0. Once draining has advanced
+// the window, `buf[0]` can no longer be distinguished from a real stream
+// start, so patterns relying on `\A`/`\z` for correctness partway
+// through a stream should not be fed through this API.
+pub struct Stream {
+    re: *const Regex,
+    window_len: usize,
+    buf: Vec<u8>,
+    offset: usize,
+    pending: VecDeque<rure_match>,
+    last_match: Option<usize>,
+}
+
 pub struct IterCaptureNames {
     capture_names: bytes::CaptureNames<'static>,
     name_ptrs: Vec<*mut c_char>,
@@ -443,6 +508,220 @@ ffi_fn! {
     }
 }
 
+fn slot_range(
+    caps: &[Option<usize>],
+    group: usize,
+    haystack_len: usize,
+) -> Option<(usize, usize)> {
+    if group * 2 + 1 >= caps.len() {
+        return None;
+    }
+    match (caps[group * 2], caps[group * 2 + 1]) {
+        (Some(s), Some(e)) if e <= haystack_len => Some((s, e)),
+        _ => None,
+    }
+}
+
+fn is_cap_letter(b: u8) -> bool {
+    (b >= b'0' && b <= b'9')
+    || (b >= b'a' && b <= b'z')
+    || (b >= b'A' && b <= b'Z')
+    || b == b'_'
+}
+
+// Parses a capture reference (`$1`, `${1}`, `$name` or `${name}`) at the
+// start of `tpl` (`tpl[0]` must be `$`). Returns the name/index bytes and
+// the number of bytes of `tpl` consumed, including the leading `$`.
+fn find_cap_ref(tpl: &[u8]) -> Option<(&[u8], usize)> {
+    if tpl.len() < 2 || tpl[0] != b'$' {
+        return None;
+    }
+    if tpl[1] == b'{' {
+        let mut i = 2;
+        while i < tpl.len() && tpl[i] != b'}' {
+            i += 1;
+        }
+        if i == tpl.len() {
+            return None;
+        }
+        Some((&tpl[2..i], i + 1))
+    } else {
+        let mut i = 1;
+        while i < tpl.len() && is_cap_letter(tpl[i]) {
+            i += 1;
+        }
+        if i == 1 {
+            return None;
+        }
+        Some((&tpl[1..i], i))
+    }
+}
+
+// Expands `template` into `dst`, resolving `$1`/`${1}` as numeric group
+// references and `$name`/`${name}` as named group references (via
+// `re.capture_names`), with `$$` as an escaped literal `$`. Group text is
+// pulled out of `haystack` using the offsets in `caps`. Unresolved or
+// unparseable references are dropped or copied through literally, mirroring
+// the replacement syntax used by `Regex::replace`. `caps` and `haystack`
+// are independent caller-supplied arguments (see `rure_captures_expand`),
+// so a group whose offsets fall outside `haystack` is dropped the same
+// way an out-of-range or unresolved group reference is.
+fn expand(
+    re: &Regex,
+    haystack: &[u8],
+    caps: &[Option<usize>],
+    template: &[u8],
+    dst: &mut Vec<u8>,
+) {
+    let mut rest = template;
+    while !rest.is_empty() {
+        match rest.iter().position(|&b| b == b'$') {
+            None => {
+                dst.extend_from_slice(rest);
+                return;
+            }
+            Some(i) => {
+                dst.extend_from_slice(&rest[..i]);
+                rest = &rest[i..];
+            }
+        }
+        if rest.len() >= 2 && rest[1] == b'$' {
+            dst.push(b'$');
+            rest = &rest[2..];
+            continue;
+        }
+        match find_cap_ref(rest) {
+            None => {
+                dst.push(b'$');
+                rest = &rest[1..];
+            }
+            Some((name, len)) => {
+                let group = match str::from_utf8(name) {
+                    Ok(name) if !name.is_empty()
+                            && name.bytes().all(|b| b >= b'0' && b <= b'9') => {
+                        name.parse::<usize>().ok()
+                    }
+                    Ok(name) => re.capture_names.get(name).map(|&i| i as usize),
+                    Err(_) => None,
+                };
+                if let Some((s, e)) =
+                        group.and_then(|g| slot_range(caps, g, haystack.len())) {
+                    dst.extend_from_slice(&haystack[s..e]);
+                }
+                rest = &rest[len..];
+            }
+        }
+    }
+}
+
+fn write_buf(src: &[u8], buf: *mut u8, buf_cap: size_t, buf_len: *mut size_t) {
+    unsafe {
+        if !buf_len.is_null() {
+            *buf_len = src.len();
+        }
+        if !buf.is_null() && buf_cap > 0 {
+            let n = cmp::min(buf_cap, src.len());
+            ptr::copy_nonoverlapping(src.as_ptr(), buf, n);
+        }
+    }
+}
+
+// Unlike `rure_replace`/`rure_replace_all`, this can't delegate to
+// `bytes::Regex`'s own replace machinery: `captures` is the capi's raw
+// `Vec<Option<usize>>` slots, not a `bytes::Captures` produced by a fresh
+// search, so expansion has to be hand-rolled here via `expand()`.
+ffi_fn! {
+    fn rure_captures_expand(
+        re: *const Regex,
+        haystack: *const u8,
+        haystack_len: size_t,
+        captures: *const Captures,
+        replacement: *const u8,
+        replacement_len: size_t,
+        flags: u32,
+        buf: *mut u8,
+        buf_cap: size_t,
+        buf_len: *mut size_t,
+    ) -> bool {
+        let re = unsafe { &*re };
+        let haystack = unsafe { slice::from_raw_parts(haystack, haystack_len) };
+        let caps = unsafe { &(*captures).0 };
+        let template =
+            unsafe { slice::from_raw_parts(replacement, replacement_len) };
+
+        let mut dst = Vec::with_capacity(replacement_len);
+        if flags & RURE_REPLACE_LITERAL > 0 {
+            dst.extend_from_slice(template);
+        } else {
+            expand(re, haystack, caps, template, &mut dst);
+        }
+        write_buf(&dst, buf, buf_cap, buf_len);
+        true
+    }
+}
+
+ffi_fn! {
+    fn rure_replace(
+        re: *const Regex,
+        haystack: *const u8,
+        haystack_len: size_t,
+        replacement: *const u8,
+        replacement_len: size_t,
+        flags: u32,
+        buf: *mut u8,
+        buf_cap: size_t,
+        buf_len: *mut size_t,
+    ) -> bool {
+        let re = unsafe { &*re };
+        let haystack = unsafe { slice::from_raw_parts(haystack, haystack_len) };
+        let template =
+            unsafe { slice::from_raw_parts(replacement, replacement_len) };
+
+        let result = if flags & RURE_REPLACE_LITERAL > 0 {
+            re.replace(haystack, bytes::NoExpand(template))
+        } else {
+            re.replace(haystack, template)
+        };
+        let found = match result {
+            Cow::Owned(_) => true,
+            Cow::Borrowed(_) => false,
+        };
+        write_buf(&result, buf, buf_cap, buf_len);
+        found
+    }
+}
+
+ffi_fn! {
+    fn rure_replace_all(
+        re: *const Regex,
+        haystack: *const u8,
+        haystack_len: size_t,
+        replacement: *const u8,
+        replacement_len: size_t,
+        flags: u32,
+        buf: *mut u8,
+        buf_cap: size_t,
+        buf_len: *mut size_t,
+    ) -> bool {
+        let re = unsafe { &*re };
+        let haystack = unsafe { slice::from_raw_parts(haystack, haystack_len) };
+        let template =
+            unsafe { slice::from_raw_parts(replacement, replacement_len) };
+
+        let result = if flags & RURE_REPLACE_LITERAL > 0 {
+            re.replace_all(haystack, bytes::NoExpand(template))
+        } else {
+            re.replace_all(haystack, template)
+        };
+        let found = match result {
+            Cow::Owned(_) => true,
+            Cow::Borrowed(_) => false,
+        };
+        write_buf(&result, buf, buf_cap, buf_len);
+        found
+    }
+}
+
 ffi_fn! {
     fn rure_options_new() -> *mut Options {
         Box::into_raw(Box::new(Options::default()))
@@ -592,3 +871,629 @@ ffi_fn! {
         unsafe { (*re).pattern_count }
     }
 }
+
+// Finds the leftmost match across every pattern in `re`, breaking ties
+// between patterns that start at the same position in favor of the
+// lowest pattern index. This is the same leftmost-first preference that
+// `Regex` gives the first alternative in `a|b`, applied across patterns
+// instead of across branches of a single pattern.
+//
+// This drives `re`'s combined `Exec` directly with a slot buffer sized
+// for every pattern in the set (`slots[2*i]`/`slots[2*i+1]` hold pattern
+// `i`'s match span), so one search of the haystack recovers every
+// pattern's leftmost match, rather than re-scanning the haystack once
+// per pattern with a second, independently compiled `Regex`.
+fn leftmost_set_match(
+    re: &RegexSet,
+    haystack: &[u8],
+    start: usize,
+) -> Option<(usize, usize, usize)> {
+    let mut slots = vec![None; 2 * re.pattern_count];
+    re.searcher().read_captures_at(&mut slots, haystack, start);
+
+    let mut best: Option<(usize, usize, usize)> = None;
+    for i in 0..re.pattern_count {
+        if let (Some(s), Some(e)) = (slots[i * 2], slots[i * 2 + 1]) {
+            let is_better = match best {
+                None => true,
+                Some((_, best_s, _)) => s < best_s,
+            };
+            if is_better {
+                best = Some((i, s, e));
+            }
+        }
+    }
+    best
+}
+
+ffi_fn! {
+    fn rure_set_find(
+        re: *const RegexSet,
+        haystack: *const u8,
+        len: size_t,
+        start: size_t,
+        pattern_index: *mut size_t,
+        match_info: *mut rure_match,
+    ) -> bool {
+        let re = unsafe { &*re };
+        let haystack = unsafe { slice::from_raw_parts(haystack, len) };
+        match leftmost_set_match(re, haystack, start) {
+            None => false,
+            Some((i, s, e)) => {
+                unsafe {
+                    if !pattern_index.is_null() {
+                        *pattern_index = i;
+                    }
+                    if !match_info.is_null() {
+                        (*match_info).start = s;
+                        (*match_info).end = e;
+                    }
+                }
+                true
+            }
+        }
+    }
+}
+
+ffi_fn! {
+    fn rure_set_iter_new(re: *const RegexSet) -> *mut SetIter {
+        Box::into_raw(Box::new(SetIter {
+            re: re,
+            last_end: 0,
+            last_match: None,
+        }))
+    }
+}
+
+ffi_fn! {
+    fn rure_set_iter_free(it: *mut SetIter) {
+        unsafe { Box::from_raw(it); }
+    }
+}
+
+ffi_fn! {
+    fn rure_set_iter_next(
+        it: *mut SetIter,
+        haystack: *const u8,
+        len: size_t,
+        pattern_index: *mut size_t,
+        match_info: *mut rure_match,
+    ) -> bool {
+        let it = unsafe { &mut *it };
+        let re = unsafe { &*it.re };
+        let text = unsafe { slice::from_raw_parts(haystack, len) };
+        if it.last_end > text.len() {
+            return false;
+        }
+        let (i, s, e) = match leftmost_set_match(re, text, it.last_end) {
+            None => return false,
+            Some(m) => m,
+        };
+        if s == e {
+            // This is an empty match. To ensure we make progress, start
+            // the next search at the smallest possible starting position
+            // of the next match following this one.
+            it.last_end += 1;
+            // Don't accept empty matches immediately following a match.
+            // Just move on to the next match.
+            if Some(e) == it.last_match {
+                return rure_set_iter_next(
+                    it, haystack, len, pattern_index, match_info);
+            }
+        } else {
+            it.last_end = e;
+        }
+        it.last_match = Some(e);
+        unsafe {
+            if !pattern_index.is_null() {
+                *pattern_index = i;
+            }
+            if !match_info.is_null() {
+                (*match_info).start = s;
+                (*match_info).end = e;
+            }
+        }
+        true
+    }
+}
+
+// Searches `stream.buf` from its start, queueing every match whose end is
+// far enough from the buffer's tail to be safe from truncation by a chunk
+// that hasn't arrived yet, then drains the consumed (and now-unneeded)
+// prefix of the buffer. `finalize` disables the tail-safety margin, since
+// `rure_stream_end` knows no more bytes are coming.
+fn stream_drain(stream: &mut Stream, finalize: bool) {
+    let re = unsafe { &*stream.re };
+    let mut search_from = 0;
+    // The highest buffer offset the matches found so far allow dropping:
+    // either the end of the last confirmed match, the start of a match
+    // deferred by the tail-safety margin, or the whole buffer once
+    // `find_at` finds no match left in it. Unless finalizing, the drain
+    // below still keeps the last `window_len` bytes, where a match that
+    // later chunks complete can start.
+    let mut safe_to_drop = 0;
+    loop {
+        if search_from > stream.buf.len() {
+            safe_to_drop = stream.buf.len();
+            break;
+        }
+        let (s, e) = match re.find_at(&stream.buf, search_from) {
+            None => {
+                safe_to_drop = stream.buf.len();
+                break;
+            }
+            Some((s, e)) => (s, e),
+        };
+        if !finalize && stream.buf.len() - e < stream.window_len {
+            // This match might still be truncated or shifted by bytes
+            // that haven't arrived yet. Wait for the next chunk; the
+            // bytes before its start are dropped only down to the last
+            // `window_len` bytes of the buffer.
+            safe_to_drop = s;
+            break;
+        }
+        if s == e && Some(stream.offset + e) == stream.last_match {
+            // Don't accept an empty match immediately following the
+            // previous match, the same way `rure_iter_next` skips it.
+            // `last_match` is absolute so it survives the drain below.
+            search_from = e + 1;
+            continue;
+        }
+        stream.pending.push_back(rure_match {
+            start: stream.offset + s,
+            end: stream.offset + e,
+        });
+        stream.last_match = Some(stream.offset + e);
+        search_from = if e > s { e } else { e + 1 };
+        safe_to_drop = e;
+    }
+
+    let retain = if finalize { 0 } else { stream.window_len };
+    let floor = stream.buf.len().saturating_sub(retain);
+    let keep_from = cmp::min(safe_to_drop, floor);
+    if keep_from > 0 {
+        stream.buf.drain(..keep_from);
+        stream.offset += keep_from;
+    }
+}
+
+ffi_fn! {
+    fn rure_stream_new(re: *const Regex, window_len: size_t) -> *mut Stream {
+        Box::into_raw(Box::new(Stream {
+            re: re,
+            window_len: window_len,
+            buf: Vec::new(),
+            offset: 0,
+            pending: VecDeque::new(),
+            last_match: None,
+        }))
+    }
+}
+
+ffi_fn! {
+    fn rure_stream_free(stream: *mut Stream) {
+        unsafe { Box::from_raw(stream); }
+    }
+}
+
+ffi_fn! {
+    fn rure_stream_feed(stream: *mut Stream, data: *const u8, len: size_t) {
+        let stream = unsafe { &mut *stream };
+        let data = unsafe { slice::from_raw_parts(data, len) };
+        stream.buf.extend_from_slice(data);
+        stream_drain(stream, false);
+    }
+}
+
+ffi_fn! {
+    fn rure_stream_next(
+        stream: *mut Stream,
+        match_info: *mut rure_match,
+    ) -> bool {
+        let stream = unsafe { &mut *stream };
+        match stream.pending.pop_front() {
+            None => false,
+            Some(m) => {
+                if !match_info.is_null() {
+                    unsafe {
+                        *match_info = m;
+                    }
+                }
+                true
+            }
+        }
+    }
+}
+
+ffi_fn! {
+    fn rure_stream_end(stream: *mut Stream) {
+        let stream = unsafe { &mut *stream };
+        stream_drain(stream, true);
+    }
+}
+
+ffi_fn! {
+    fn rure_split_new(re: *const Regex) -> *mut Split {
+        Box::into_raw(Box::new(Split {
+            re: re,
+            piece_start: 0,
+            search_pos: 0,
+            last_match: None,
+            limit: None,
+            done: false,
+        }))
+    }
+}
+
+ffi_fn! {
+    fn rure_splitn_new(re: *const Regex, limit: size_t) -> *mut Split {
+        Box::into_raw(Box::new(Split {
+            re: re,
+            piece_start: 0,
+            search_pos: 0,
+            last_match: None,
+            limit: Some(limit),
+            done: false,
+        }))
+    }
+}
+
+ffi_fn! {
+    fn rure_split_free(it: *mut Split) {
+        unsafe { Box::from_raw(it); }
+    }
+}
+
+ffi_fn! {
+    fn rure_split_next(
+        it: *mut Split,
+        haystack: *const u8,
+        len: size_t,
+        match_info: *mut rure_match,
+    ) -> bool {
+        let it = unsafe { &mut *it };
+        let re = unsafe { &*it.re };
+        let text = unsafe { slice::from_raw_parts(haystack, len) };
+
+        if it.done || it.limit == Some(0) {
+            it.done = true;
+            return false;
+        }
+        if it.piece_start > text.len() {
+            it.done = true;
+            return false;
+        }
+        // `splitn`'s final piece is always the remainder of the haystack,
+        // regardless of whether a further match exists.
+        if it.limit == Some(1) {
+            it.done = true;
+            it.limit = Some(0);
+            if !match_info.is_null() {
+                unsafe {
+                    (*match_info).start = it.piece_start;
+                    (*match_info).end = text.len();
+                }
+            }
+            return true;
+        }
+        if it.search_pos > text.len() {
+            it.done = true;
+            if !match_info.is_null() {
+                unsafe {
+                    (*match_info).start = it.piece_start;
+                    (*match_info).end = text.len();
+                }
+            }
+            return true;
+        }
+        let (s, e) = match re.find_at(text, it.search_pos) {
+            None => {
+                it.done = true;
+                if !match_info.is_null() {
+                    unsafe {
+                        (*match_info).start = it.piece_start;
+                        (*match_info).end = text.len();
+                    }
+                }
+                return true;
+            }
+            Some((s, e)) => (s, e),
+        };
+        if s == e && Some(e) == it.last_match {
+            // Don't split on an empty match immediately following the
+            // previous match. Advance the search-resume cursor past it
+            // and keep looking, the same way `rure_iter_next` skips it —
+            // `piece_start` is left untouched, since it must stay at the
+            // true end of the last *accepted* match.
+            it.search_pos = e + 1;
+            return rure_split_next(it, haystack, len, match_info);
+        }
+        let piece_start = it.piece_start;
+        it.last_match = Some(e);
+        it.piece_start = e;
+        it.search_pos = if e == s { e + 1 } else { e };
+        if let Some(n) = it.limit {
+            it.limit = Some(n - 1);
+        }
+        if !match_info.is_null() {
+            unsafe {
+                (*match_info).start = piece_start;
+                (*match_info).end = s;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(pattern: &str) -> *const Regex {
+        let mut err = Error::new(ErrorKind::None);
+        let re = rure_compile(
+            pattern.as_ptr(), pattern.len(), RURE_DEFAULT_FLAGS,
+            ptr::null(), &mut err);
+        assert!(!re.is_null(), "failed to compile {:?}", pattern);
+        re
+    }
+
+    fn split_pieces(
+        it: *mut Split,
+        haystack: &[u8],
+    ) -> Vec<Vec<u8>> {
+        let mut pieces = vec![];
+        let mut m = rure_match { start: 0, end: 0 };
+        while rure_split_next(it, haystack.as_ptr(), haystack.len(), &mut m) {
+            pieces.push(haystack[m.start..m.end].to_vec());
+        }
+        pieces
+    }
+
+    #[test]
+    fn split_empty_match_keeps_piece_boundary_separate_from_cursor() {
+        let re = compile("a*");
+        let it = rure_split_new(re);
+        let pieces = split_pieces(it, b"ba");
+        assert_eq!(
+            pieces,
+            vec![b"".to_vec(), b"b".to_vec(), b"".to_vec()]);
+        rure_split_free(it);
+        rure_free(re);
+    }
+
+    #[test]
+    fn splitn_zero_yields_no_pieces() {
+        let re = compile(",");
+        let it = rure_splitn_new(re, 0);
+        let pieces = split_pieces(it, b"a,b,c");
+        assert!(pieces.is_empty());
+        rure_split_free(it);
+        rure_free(re);
+    }
+
+    #[test]
+    fn splitn_one_yields_whole_haystack() {
+        let re = compile(",");
+        let it = rure_splitn_new(re, 1);
+        let pieces = split_pieces(it, b"a,b,c");
+        assert_eq!(pieces, vec![b"a,b,c".to_vec()]);
+        rure_split_free(it);
+        rure_free(re);
+    }
+
+    #[test]
+    fn stream_buffer_does_not_grow_without_bound_on_non_matches() {
+        let re = compile("xyz");
+        let window_len = 3;
+        let stream = rure_stream_new(re, window_len);
+        for _ in 0..20 {
+            let chunk = vec![b'a'; 10];
+            rure_stream_feed(stream, chunk.as_ptr(), chunk.len());
+            let buf_len = unsafe { (*stream).buf.len() };
+            assert!(
+                buf_len <= window_len,
+                "stream buffer grew to {} bytes (window_len {})",
+                buf_len, window_len);
+        }
+        rure_stream_free(stream);
+        rure_free(re);
+    }
+
+    #[test]
+    fn stream_end_does_not_report_spurious_trailing_empty_match() {
+        let re = compile("a*");
+        let stream = rure_stream_new(re, 10);
+        rure_stream_feed(stream, b"aaa".as_ptr(), 3);
+        rure_stream_end(stream);
+
+        let mut matches = vec![];
+        let mut m = rure_match { start: 0, end: 0 };
+        while rure_stream_next(stream, &mut m) {
+            matches.push((m.start, m.end));
+        }
+        assert_eq!(matches, vec![(0, 3)]);
+        rure_stream_free(stream);
+        rure_free(re);
+    }
+
+    fn replace(
+        re: *const Regex,
+        haystack: &[u8],
+        replacement: &[u8],
+        flags: u32,
+    ) -> (bool, Vec<u8>) {
+        let mut buf = vec![0u8; haystack.len() + replacement.len() + 16];
+        let mut buf_len = 0;
+        let found = rure_replace(
+            re, haystack.as_ptr(), haystack.len(),
+            replacement.as_ptr(), replacement.len(), flags,
+            buf.as_mut_ptr(), buf.len(), &mut buf_len);
+        buf.truncate(buf_len);
+        (found, buf)
+    }
+
+    fn replace_all(
+        re: *const Regex,
+        haystack: &[u8],
+        replacement: &[u8],
+        flags: u32,
+    ) -> (bool, Vec<u8>) {
+        let mut buf = vec![0u8; haystack.len() * 4 + replacement.len() + 16];
+        let mut buf_len = 0;
+        let found = rure_replace_all(
+            re, haystack.as_ptr(), haystack.len(),
+            replacement.as_ptr(), replacement.len(), flags,
+            buf.as_mut_ptr(), buf.len(), &mut buf_len);
+        buf.truncate(buf_len);
+        (found, buf)
+    }
+
+    #[test]
+    fn replace_expands_numeric_and_named_refs() {
+        let re = compile(r"(?P<word>a)(b)");
+        let (found, out) = replace(re, b"ab", b"$2-${word}", 0);
+        assert!(found);
+        assert_eq!(out, b"b-a".to_vec());
+        rure_free(re);
+    }
+
+    #[test]
+    fn replace_with_literal_flag_does_not_expand() {
+        let re = compile(r"(?P<word>a)(b)");
+        let (found, out) = replace(
+            re, b"ab", b"$2-${word}", RURE_REPLACE_LITERAL);
+        assert!(found);
+        assert_eq!(out, b"$2-${word}".to_vec());
+        rure_free(re);
+    }
+
+    #[test]
+    fn replace_reports_no_match() {
+        let re = compile("z");
+        let (found, out) = replace(re, b"ab", b"x", 0);
+        assert!(!found);
+        assert_eq!(out, b"ab".to_vec());
+        rure_free(re);
+    }
+
+    #[test]
+    fn replace_all_replaces_every_match() {
+        let re = compile("a");
+        let (found, out) = replace_all(re, b"aaa", b"b", 0);
+        assert!(found);
+        assert_eq!(out, b"bbb".to_vec());
+        rure_free(re);
+    }
+
+    fn captures_for(re: *const Regex, haystack: &[u8]) -> *mut Captures {
+        let caps = rure_captures_new(re);
+        let found = rure_find_captures(
+            re, haystack.as_ptr(), haystack.len(), 0, caps);
+        assert!(found);
+        caps
+    }
+
+    fn expand_into(
+        re: *const Regex,
+        haystack: &[u8],
+        caps: *const Captures,
+        template: &[u8],
+        flags: u32,
+    ) -> Vec<u8> {
+        let mut buf = vec![0u8; haystack.len() + template.len() + 32];
+        let mut buf_len = 0;
+        let ok = rure_captures_expand(
+            re, haystack.as_ptr(), haystack.len(), caps,
+            template.as_ptr(), template.len(), flags,
+            buf.as_mut_ptr(), buf.len(), &mut buf_len);
+        assert!(ok);
+        buf.truncate(buf_len);
+        buf
+    }
+
+    #[test]
+    fn captures_expand_resolves_numeric_named_and_dollar_refs() {
+        let re = compile(r"(?P<word>a)(b)");
+        let caps = captures_for(re, b"ab");
+        let out = expand_into(
+            re, b"ab", caps, b"$2-${word}-$$-$9-${missing}", 0);
+        assert_eq!(out, b"b-a-$--".to_vec());
+        rure_captures_free(caps);
+        rure_free(re);
+    }
+
+    #[test]
+    fn captures_expand_with_literal_flag_does_not_expand() {
+        let re = compile(r"(?P<word>a)(b)");
+        let caps = captures_for(re, b"ab");
+        let out = expand_into(
+            re, b"ab", caps, b"$1 literal", RURE_REPLACE_LITERAL);
+        assert_eq!(out, b"$1 literal".to_vec());
+        rure_captures_free(caps);
+        rure_free(re);
+    }
+
+    fn compile_set(patterns: &[&str]) -> *const RegexSet {
+        let raw_pats: Vec<*const u8> =
+            patterns.iter().map(|p| p.as_ptr()).collect();
+        let raw_lens: Vec<size_t> =
+            patterns.iter().map(|p| p.len()).collect();
+        let mut err = Error::new(ErrorKind::None);
+        let set = rure_compile_set(
+            raw_pats.as_ptr(), raw_lens.as_ptr(), patterns.len(),
+            RURE_DEFAULT_FLAGS, ptr::null(), &mut err);
+        assert!(!set.is_null(), "failed to compile set {:?}", patterns);
+        set
+    }
+
+    // A pattern with its own capturing groups must still report its
+    // *own* overall match span in `leftmost_set_match`'s slots, not one
+    // skewed by its internal group count — `slots[2*i]`/`slots[2*i+1]`
+    // is pattern `i`'s match, regardless of how many groups pattern `i`
+    // itself contains.
+    #[test]
+    fn set_find_reports_own_span_for_pattern_with_capture_groups() {
+        let set = compile_set(&["(a)(b)", "c"]);
+        let haystack = b"zzabzzc";
+        let mut idx = 0;
+        let mut m = rure_match { start: 0, end: 0 };
+        let found = rure_set_find(
+            set, haystack.as_ptr(), haystack.len(), 0, &mut idx, &mut m);
+        assert!(found);
+        assert_eq!(idx, 0);
+        assert_eq!((m.start, m.end), (2, 4));
+        rure_set_free(set);
+    }
+
+    #[test]
+    fn set_find_breaks_ties_by_lowest_pattern_index() {
+        let set = compile_set(&["a", "ab"]);
+        let haystack = b"ab";
+        let mut idx = 1;
+        let mut m = rure_match { start: 0, end: 0 };
+        let found = rure_set_find(
+            set, haystack.as_ptr(), haystack.len(), 0, &mut idx, &mut m);
+        assert!(found);
+        assert_eq!(idx, 0);
+        assert_eq!((m.start, m.end), (0, 1));
+        rure_set_free(set);
+    }
+
+    #[test]
+    fn set_iter_next_enumerates_matches_in_order() {
+        let set = compile_set(&["(a)(b)", "c"]);
+        let haystack = b"ab..c..ab";
+        let it = rure_set_iter_new(set);
+        let mut got = vec![];
+        let mut idx = 0;
+        let mut m = rure_match { start: 0, end: 0 };
+        while rure_set_iter_next(
+            it, haystack.as_ptr(), haystack.len(), &mut idx, &mut m) {
+            got.push((idx, m.start, m.end));
+        }
+        assert_eq!(got, vec![(0, 0, 2), (1, 4, 5), (0, 7, 9)]);
+        rure_set_iter_free(it);
+        rure_set_free(set);
+    }
+}